@@ -12,7 +12,27 @@ use serde::Deserialize;
 use std::collections::BTreeMap;
 use std::fs;
 
+use nvml_wrapper::enum_wrappers::device::{GpuInstanceProfile, MigMode as NvmlMigMode};
+use nvml_wrapper::error::NvmlError;
+use nvml_wrapper::struct_wrappers::device::GpuInstanceProfileInfo;
+use nvml_wrapper::{Device, Nvml};
+
+/// The GPU-instance profile slots NVML exposes per device. Not every slot is
+/// valid on every board (e.g. a T4 has none of these), so lookups probe each
+/// one and skip the ones that come back `NotFound`.
+const GPU_INSTANCE_PROFILE_SLOTS: &[GpuInstanceProfile] = &[
+    GpuInstanceProfile::Profile1Slice,
+    GpuInstanceProfile::Profile2Slice,
+    GpuInstanceProfile::Profile3Slice,
+    GpuInstanceProfile::Profile4Slice,
+    GpuInstanceProfile::Profile6Slice,
+    GpuInstanceProfile::Profile7Slice,
+    GpuInstanceProfile::Profile8Slice,
+];
+
 const DEFAULT_CONFIG_PATH: &str = "/etc/bootstrap-commands/bootstrap-commands.toml";
+const REBOOT_GUARD_STATE_DIR: &str = "/var/lib/nvidia-mig-manager";
+const REBOOT_GUARD_STATE_FILE: &str = "state";
 
 /*
 ./nvidia-smi --query-gpu=gpu_name,mig.mode.current,mig.mode.pending --format=csv,noheader
@@ -45,10 +65,126 @@ Allocatable:
   pods:               737
 */
 
+/// The overall MIG layout strategy for the node: `single` applies one shared
+/// `profiles` list to every MIG-capable GPU, while `mixed` lets each GPU
+/// index or UUID (see `GpuMigInfo::uuid`) carry its own profile list via
+/// `gpu_profiles`/`gpu_profiles_by_uuid`.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MigStrategy {
+    Single,
+    Mixed,
+}
+
+/// MIG profile layout read from `bootstrap-commands.toml`, naming profiles by
+/// friendly name (e.g. `3g.20gb`) which gets resolved to the board-specific
+/// profile ID via NVML before use. Which fields apply is driven by
+/// `strategy`: `single` uses `profiles` for every GPU, `mixed` uses
+/// `gpu_profiles`/`gpu_profiles_by_uuid` (UUID takes precedence over index).
+#[derive(Debug, Deserialize)]
+struct MigConfig {
+    strategy: MigStrategy,
+    #[serde(default)]
+    profiles: Vec<String>,
+    // TOML table keys are always strings, so this has to be keyed by the
+    // stringified GPU index rather than `usize` directly (toml::from_str
+    // rejects a bare integer field here with "invalid type: string").
+    #[serde(default)]
+    gpu_profiles: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    gpu_profiles_by_uuid: BTreeMap<String, Vec<String>>,
+}
+
+impl MigConfig {
+    /// Looks up the configured profile list for a device: the shared list
+    /// under `single`, or its own entry (UUID preferred over index) under
+    /// `mixed`.
+    fn profiles_for(&self, device: &GpuMigInfo) -> Option<&Vec<String>> {
+        match self.strategy {
+            MigStrategy::Single => {
+                if self.profiles.is_empty() {
+                    None
+                } else {
+                    Some(&self.profiles)
+                }
+            }
+            MigStrategy::Mixed => self
+                .gpu_profiles_by_uuid
+                .get(&device.uuid)
+                .or_else(|| self.gpu_profiles.get(&device.index.to_string())),
+        }
+    }
+
+    /// Validates the config against the GPUs actually present and against
+    /// the chosen strategy: `single` requires exactly one shared `profiles`
+    /// list, `mixed` requires every configured index/UUID to exist.
+    fn validate(&self, devices: &[GpuMigInfo]) -> Result<()> {
+        match self.strategy {
+            MigStrategy::Single => {
+                ensure!(
+                    !self.profiles.is_empty(),
+                    error::InvalidMigConfigSnafu {
+                        message: "strategy is 'single' but no 'profiles' list was given",
+                    }
+                );
+                ensure!(
+                    self.gpu_profiles.is_empty() && self.gpu_profiles_by_uuid.is_empty(),
+                    error::InvalidMigConfigSnafu {
+                        message: "strategy is 'single' but per-GPU 'gpu_profiles'/'gpu_profiles_by_uuid' were also given",
+                    }
+                );
+            }
+            MigStrategy::Mixed => {
+                for index_str in self.gpu_profiles.keys() {
+                    let index: usize = index_str.parse().context(error::ConfigGpuIndexSnafu {
+                        index: index_str.clone(),
+                    })?;
+                    ensure!(
+                        index < devices.len(),
+                        error::InvalidMigConfigSnafu {
+                            message: format!(
+                                "config references GPU index {} but only {} GPU(s) are present",
+                                index,
+                                devices.len()
+                            ),
+                        }
+                    );
+                }
+
+                for uuid in self.gpu_profiles_by_uuid.keys() {
+                    ensure!(
+                        devices.iter().any(|device| &device.uuid == uuid),
+                        error::InvalidMigConfigSnafu {
+                            message: format!("config references GPU UUID {} but no such GPU is present", uuid),
+                        }
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn load_mig_config(config_path: &Path) -> Result<MigConfig> {
+    let contents = fs::read_to_string(config_path).context(error::ConfigReadSnafu {
+        path: config_path,
+    })?;
+
+    toml::from_str(&contents).context(error::ConfigParseSnafu {
+        path: config_path,
+    })
+}
+
 /// Stores user-supplied global arguments
 struct Args {
     log_level: LevelFilter,
     config_path: PathBuf,
+    // Drivers that fail `Nvml::init()` (e.g. vGPU hosts missing the NVML shared
+    // library) can still be served by shelling out to nvidia-smi; this is off by
+    // default since the NVML path reports structured errors instead of silently
+    // mapping unparsed rows to `MigState::Unknown`.
+    allow_shellout_fallback: bool,
 }
 
 impl Default for Args {
@@ -56,6 +192,7 @@ impl Default for Args {
         Self {
             log_level: LevelFilter::Info,
             config_path: PathBuf::from_str(DEFAULT_CONFIG_PATH).unwrap(),
+            allow_shellout_fallback: false,
         }
     }
 }
@@ -94,25 +231,44 @@ enum MigState {
     Unknown,
 }
 
-fn is_mig_capable(modes: &[(String, MigState, MigState)]) -> bool {
-    for (_, current, pending) in modes {
-        // Early exit because all the GPUs are of same make and model
-        if *current != MigState::Unknown || *pending != MigState::Unknown {
-            return true;
-        }
-    }
+/// One GPU's observed MIG state. A mixed-GPU host may have only some devices
+/// be MIG-capable, so capability and target mode are computed per device
+/// (see `is_mig_capable`) rather than assumed uniform across the fleet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GpuMigInfo {
+    /// Stable NVML UUID for this device. Unlike `index`, this doesn't move
+    /// around across reboots, so it's the key config (`MigConfig`) and
+    /// persisted state should prefer when targeting a specific GPU.
+    uuid: String,
+    name: String,
+    index: u32,
+    current: MigState,
+    pending: MigState,
+}
 
-    return false
+/// Whether a single device is MIG-capable, judged from its own current/pending
+/// state rather than assumed from the rest of the fleet, so a node mixing an
+/// A100 with a T4 only targets the A100.
+fn is_mig_capable(device: &GpuMigInfo) -> bool {
+    device.current != MigState::Unknown || device.pending != MigState::Unknown
 }
 
+/// Filters down to the MIG-capable devices in the query result.
+fn mig_capable_devices(devices: &[GpuMigInfo]) -> Vec<GpuMigInfo> {
+    devices
+        .iter()
+        .filter(|device| is_mig_capable(device))
+        .cloned()
+        .collect()
+}
 
-fn get_mig_mode(modes: &[(String, MigState, MigState)]) -> MigState {
+fn get_mig_mode(devices: &[GpuMigInfo]) -> MigState {
     let mut current_states = HashSet::new();
     let mut pending_states = HashSet::new();
 
-    for (_, current, pending) in modes {
-        current_states.insert(current.clone());
-        pending_states.insert(pending.clone());
+    for device in devices {
+        current_states.insert(device.current.clone());
+        pending_states.insert(device.pending.clone());
     }
 
     if current_states.len() == 1 && pending_states.len() == 1 {
@@ -133,56 +289,411 @@ fn get_mig_mode(modes: &[(String, MigState, MigState)]) -> MigState {
     }
 }
 
-fn set_mig_mode() -> Result<()> {
-    let _ = command("/usr/libexec/nvidia/tesla/bin/nvidia-smi", ["-mig", "1"])?;
+/// Maps an NVML-reported MIG mode onto our own `MigState`. NVML only ever
+/// reports `Enabled`/`Disabled` per device; `Transition`/`Unknown` are derived
+/// across the whole device set in `get_mig_mode` (a device that doesn't
+/// support MIG at all is recorded as `Unknown` in `run_gpu_query_nvml`).
+fn from_nvml_mig_mode(mode: NvmlMigMode) -> MigState {
+    match mode {
+        NvmlMigMode::Enabled => MigState::Enabled,
+        NvmlMigMode::Disabled => MigState::Disabled,
+    }
+}
+
+fn set_mig_mode_nvml(capable: &[GpuMigInfo]) -> Result<()> {
+    let nvml = Nvml::init().context(error::NvmlSnafu)?;
+
+    for device_info in capable {
+        let mut device = nvml
+            .device_by_index(device_info.index)
+            .context(error::NvmlSnafu)?;
+        device.set_mig_mode(NvmlMigMode::Enabled).context(error::NvmlSnafu)?;
+    }
+
+    Ok(())
+}
+
+fn set_mig_mode_shellout(capable: &[GpuMigInfo]) -> Result<()> {
+    for device_info in capable {
+        let args = vec![
+            "-i".to_string(),
+            device_info.index.to_string(),
+            "-mig".to_string(),
+            "1".to_string(),
+        ];
+        let _ = command("/usr/libexec/nvidia/tesla/bin/nvidia-smi", args)?;
+    }
+
     Ok(())
 }
 
-fn analyze_mig_status(mig_modes: &Vec<(String, MigState, MigState)>) -> Result<(bool, MigState)> {
+fn set_mig_mode(args: &Args, capable: &[GpuMigInfo]) -> Result<()> {
+    match set_mig_mode_nvml(capable) {
+        Ok(()) => Ok(()),
+        Err(source) if args.allow_shellout_fallback => {
+            info!("NVML set_mig_mode failed ({}), falling back to nvidia-smi", source);
+            set_mig_mode_shellout(capable)
+        }
+        Err(source) => Err(source),
+    }
+}
+
+fn analyze_mig_status(devices: &[GpuMigInfo]) -> Result<(bool, MigState)> {
     info!("entered analyze_mig_status function here");
 
-    let is_mig_capable = is_mig_capable(&mig_modes);
+    let capable = mig_capable_devices(devices);
+    let is_mig_capable = !capable.is_empty();
     info!("is_mig_capable: {:?}", is_mig_capable);
 
-    let overall_mig_mode = get_mig_mode(&mig_modes);
+    let overall_mig_mode = get_mig_mode(&capable);
     info!("overall_mig_mode: {:?}", overall_mig_mode);
 
     Ok((is_mig_capable, overall_mig_mode))
 }
 
-fn set_mig_profile() -> Result<()> {
-    let _ = command("/usr/libexec/nvidia/tesla/bin/nvidia-smi", ["mig", "-cgi", "9,9", "-C"])?;
+/// Turns a raw NVML profile description into the friendly name used in config
+/// and logs, e.g. `3g.20gb` for a 3-slice, 20GB-memory instance.
+fn profile_friendly_name(info: &GpuInstanceProfileInfo) -> String {
+    let memory_gb = (info.memory_size_mb as f64 / 1024.0).round() as u64;
+    format!("{}g.{}gb", info.slice_count, memory_gb)
+}
+
+/// Builds a friendly-name -> profile ID lookup for the profiles this specific
+/// device actually supports, since the same name maps to different IDs across
+/// A100-40GB / A100-80GB / H100 boards.
+fn profile_name_lookup(device: &Device) -> Result<BTreeMap<String, u32>> {
+    let mut lookup = BTreeMap::new();
+
+    for &profile in GPU_INSTANCE_PROFILE_SLOTS {
+        match device.gpu_instance_profile_info(profile) {
+            Ok(info) => {
+                lookup.insert(profile_friendly_name(&info), info.id);
+            }
+            Err(NvmlError::NotFound) => continue,
+            Err(source) => return Err(source).context(error::NvmlSnafu),
+        }
+    }
+
+    Ok(lookup)
+}
+
+fn resolve_profile_ids(device: &Device, names: &[String]) -> Result<Vec<u32>> {
+    let lookup = profile_name_lookup(device)?;
+
+    names
+        .iter()
+        .map(|name| {
+            lookup
+                .get(name)
+                .copied()
+                .context(error::UnsupportedProfileSnafu { name: name.clone() })
+        })
+        .collect()
+}
+
+/// How many times we'll sit in `WaitForReboot` for the same target mode
+/// before concluding the reboot we asked for didn't take (rather than "just
+/// hasn't happened yet") and requesting another one.
+const MAX_REBOOT_ATTEMPTS: u32 = 3;
+
+/// Marks a MIG mode change that has been requested but only takes effect
+/// after reboot, so a later run can tell "still waiting on the reboot we
+/// already asked for" apart from "this is a fresh mode change". `run` only
+/// writes it right before issuing the reboot, after `set_mig_mode` and the
+/// re-query have succeeded, so a failure earlier in the same pass leaves no
+/// marker behind to wedge the next run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RebootGuardState {
+    target_mode: MigState,
+    reboot_requested: bool,
+    attempts: u32,
+}
+
+impl RebootGuardState {
+    fn path() -> PathBuf {
+        Path::new(REBOOT_GUARD_STATE_DIR).join(REBOOT_GUARD_STATE_FILE)
+    }
+
+    /// Parses the plain-text state format. Pulled out of `load` so the
+    /// format itself can be unit tested without touching disk.
+    fn parse(contents: &str) -> Option<Self> {
+        let mut target_mode = None;
+        let mut reboot_requested = false;
+        let mut attempts = 0;
+
+        for line in contents.lines() {
+            if let Some(value) = line.strip_prefix("target_mode=") {
+                target_mode = match value {
+                    "Enabled" => Some(MigState::Enabled),
+                    "Disabled" => Some(MigState::Disabled),
+                    _ => None,
+                };
+            } else if let Some(value) = line.strip_prefix("reboot_requested=") {
+                reboot_requested = value == "true";
+            } else if let Some(value) = line.strip_prefix("attempts=") {
+                attempts = value.parse().unwrap_or(0);
+            }
+        }
+
+        Some(Self {
+            target_mode: target_mode?,
+            reboot_requested,
+            attempts,
+        })
+    }
+
+    /// Renders the plain-text state format. Pulled out of `save` so the
+    /// format itself can be unit tested without touching disk.
+    fn serialize(&self) -> String {
+        format!(
+            "target_mode={:?}\nreboot_requested={}\nattempts={}\n",
+            self.target_mode, self.reboot_requested, self.attempts
+        )
+    }
+
+    fn load() -> Result<Option<Self>> {
+        let path = Self::path();
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let contents = fs::read_to_string(&path).context(error::StateReadSnafu { path: path.clone() })?;
+        let state = Self::parse(&contents).context(error::InvalidStateSnafu { path })?;
+        Ok(Some(state))
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path();
+        fs::create_dir_all(REBOOT_GUARD_STATE_DIR).context(error::StateWriteSnafu { path: path.clone() })?;
+        fs::write(&path, self.serialize()).context(error::StateWriteSnafu { path })
+    }
+
+    fn clear() -> Result<()> {
+        let path = Self::path();
+        if path.exists() {
+            fs::remove_file(&path).context(error::StateWriteSnafu { path })?;
+        }
+        Ok(())
+    }
+}
+
+/// What to do this run, once the reboot-guard state has been reconciled
+/// against the MIG mode actually observed on the GPUs.
+#[derive(Debug, PartialEq, Eq)]
+enum MigAction {
+    SetProfile,
+    RequestReboot,
+    WaitForReboot,
+    None,
+}
+
+/// What to do to the persisted reboot-guard marker, decided alongside the
+/// `MigAction`. Kept separate from the actual file I/O so the decision table
+/// in `decide_reboot_guard` can be unit tested without touching disk.
+#[derive(Debug, PartialEq, Eq)]
+enum GuardUpdate {
+    Clear,
+    Save(RebootGuardState),
+    None,
+}
+
+/// Decides the next `MigAction` (and what to do with the persisted marker)
+/// from the observed MIG mode and the current guard state. A reboot we
+/// already requested (`reboot_requested` with a matching `target_mode`) is
+/// bounded by `MAX_REBOOT_ATTEMPTS` regardless of whether the device reports
+/// `Disabled` (the reboot hasn't started) or `Transition` (it's started but
+/// hasn't settled on the target mode) — both are "still waiting", and both
+/// need to give up and re-request the reboot rather than wait forever.
+fn decide_reboot_guard(
+    overall_mig_mode: &MigState,
+    state: Option<&RebootGuardState>,
+) -> (MigAction, GuardUpdate) {
+    match overall_mig_mode {
+        MigState::Enabled => {
+            // Whatever mode change was in flight has completed; nothing left to guard.
+            (MigAction::SetProfile, GuardUpdate::Clear)
+        }
+
+        MigState::Disabled | MigState::Transition => {
+            match state {
+                Some(state) if state.reboot_requested && state.target_mode == MigState::Enabled => {
+                    if state.attempts >= MAX_REBOOT_ATTEMPTS {
+                        info!(
+                            "reboot to reach {:?} still hasn't taken after {} attempt(s); requesting another",
+                            state.target_mode, state.attempts
+                        );
+                        (MigAction::RequestReboot, GuardUpdate::Clear)
+                    } else {
+                        let mut next = state.clone();
+                        next.attempts += 1;
+                        info!(
+                            "reboot already requested to reach {:?} (attempt {}/{}) but current mode is still {:?}; backing off",
+                            next.target_mode, next.attempts, MAX_REBOOT_ATTEMPTS, overall_mig_mode
+                        );
+                        (MigAction::WaitForReboot, GuardUpdate::Save(next))
+                    }
+                }
+                _ if *overall_mig_mode == MigState::Disabled => {
+                    (MigAction::RequestReboot, GuardUpdate::None)
+                }
+                _ => {
+                    info!("MIG mode is transitioning to its pending value; waiting for it to settle");
+                    (MigAction::WaitForReboot, GuardUpdate::None)
+                }
+            }
+        }
+
+        MigState::Unknown => {
+            info!("MIG mode could not be determined across all GPUs; taking no action");
+            (MigAction::None, GuardUpdate::None)
+        }
+    }
+}
+
+/// Reconciles the persisted reboot-guard marker against the current,
+/// observed MIG mode so a flaky node can't enable-MIG/reboot in a loop.
+/// `RequestReboot` only records intent; the caller (`run`) is responsible
+/// for persisting the marker itself just before it issues the reboot,
+/// so an earlier failure in the same pass leaves nothing to reconcile
+/// against next time. A reboot that's still pending after `MAX_REBOOT_ATTEMPTS`
+/// runs is treated as failed-to-take and retried rather than waited on forever.
+fn reconcile_reboot_guard(overall_mig_mode: &MigState) -> Result<MigAction> {
+    let state = RebootGuardState::load()?;
+    let (action, update) = decide_reboot_guard(overall_mig_mode, state.as_ref());
+
+    match update {
+        GuardUpdate::Clear => RebootGuardState::clear()?,
+        GuardUpdate::Save(next) => next.save()?,
+        GuardUpdate::None => (),
+    }
+
+    Ok(action)
+}
+
+fn set_mig_profile(config: &MigConfig, capable: &[GpuMigInfo]) -> Result<()> {
+    let nvml = Nvml::init().context(error::NvmlSnafu)?;
+
+    for device_info in capable {
+        let Some(profile_names) = config.profiles_for(device_info) else {
+            info!(
+                "no MIG profile configured for GPU {} ({}); skipping",
+                device_info.index, device_info.uuid
+            );
+            continue;
+        };
+
+        let device = nvml
+            .device_by_index(device_info.index)
+            .context(error::NvmlSnafu)?;
+        let profile_ids = resolve_profile_ids(&device, profile_names)?;
+        let cgi_arg = profile_ids
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        info!(
+            "creating GPU instances on GPU {} ({}) using profiles {:?} (ids {})",
+            device_info.index, device_info.uuid, profile_names, cgi_arg
+        );
+
+        let args = vec![
+            "mig".to_string(),
+            "-i".to_string(),
+            device_info.index.to_string(),
+            "-cgi".to_string(),
+            cgi_arg,
+            "-C".to_string(),
+        ];
+        let _ = command("/usr/libexec/nvidia/tesla/bin/nvidia-smi", args)?;
+    }
+
     Ok(())
 }
 
-fn run_gpu_query() -> Result<Vec<(String, MigState, MigState)>> {
-    let output = command("/usr/libexec/nvidia/tesla/bin/nvidia-smi", ["--query-gpu=gpu_name,mig.mode.current,mig.mode.pending", "--format=csv,noheader"])?;
+fn run_gpu_query_nvml() -> Result<Vec<GpuMigInfo>> {
+    let nvml = Nvml::init().context(error::NvmlSnafu)?;
+    let device_count = nvml.device_count().context(error::NvmlSnafu)?;
+    let mut modes = Vec::new();
+
+    for i in 0..device_count {
+        let device = nvml.device_by_index(i).context(error::NvmlSnafu)?;
+        let gpu_name = device.name().context(error::NvmlSnafu)?;
+        let uuid = device.uuid().context(error::NvmlSnafu)?;
+        // Devices without MIG support (e.g. a T4) report `NotSupported`; they
+        // are recorded as `Unknown` so `is_mig_capable` filters them out.
+        let (current, pending) = match device.mig_mode() {
+            Ok((current, pending)) => (from_nvml_mig_mode(current), from_nvml_mig_mode(pending)),
+            Err(NvmlError::NotSupported) => (MigState::Unknown, MigState::Unknown),
+            Err(source) => return Err(source).context(error::NvmlSnafu),
+        };
+        info!("{:?}", (i, &gpu_name, &uuid, &current, &pending));
+
+        modes.push(GpuMigInfo {
+            uuid,
+            name: gpu_name,
+            index: i,
+            current,
+            pending,
+        });
+    }
+
+    Ok(modes)
+}
+
+fn run_gpu_query_shellout() -> Result<Vec<GpuMigInfo>> {
+    let output = command(
+        "/usr/libexec/nvidia/tesla/bin/nvidia-smi",
+        [
+            "--query-gpu=index,gpu_name,gpu_uuid,mig.mode.current,mig.mode.pending",
+            "--format=csv,noheader",
+        ],
+    )?;
     let mut modes = Vec::new();
 
     for line in output.lines() {
         let parts: Vec<_> = line.split(", ").collect();
         info!("{:?}", parts);
 
-        if parts.len() == 3 {
-            let current = match parts[1] {
+        if parts.len() == 5 {
+            let current = match parts[3] {
                 "Enabled" => MigState::Enabled,
                 "Disabled" => MigState::Disabled,
                 _ => MigState::Unknown,
             };
 
-            let pending = match parts[2] {
+            let pending = match parts[4] {
                 "Enabled" => MigState::Enabled,
                 "Disabled" => MigState::Disabled,
                 _ => MigState::Unknown,
             };
 
-            modes.push((parts[0].to_string(), current, pending));
+            let index = parts[0].parse().context(error::GpuIndexSnafu { index: parts[0] })?;
+
+            modes.push(GpuMigInfo {
+                uuid: parts[2].to_string(),
+                name: parts[1].to_string(),
+                index,
+                current,
+                pending,
+            });
         }
     }
 
     Ok(modes)
 }
 
+fn run_gpu_query(args: &Args) -> Result<Vec<GpuMigInfo>> {
+    match run_gpu_query_nvml() {
+        Ok(modes) => Ok(modes),
+        Err(source) if args.allow_shellout_fallback => {
+            info!("NVML query failed ({}), falling back to nvidia-smi", source);
+            run_gpu_query_shellout()
+        }
+        Err(source) => Err(source),
+    }
+}
+
 /// Parse the args to the program and return an Args struct
 fn parse_args(args: env::Args) -> Result<Args> {
     let mut global_args = Args::default();
@@ -206,6 +717,10 @@ fn parse_args(args: env::Args) -> Result<Args> {
                 global_args.config_path = PathBuf::from(config_str.as_str());
             }
 
+            "--allow-shellout-fallback" => {
+                global_args.allow_shellout_fallback = true;
+            }
+
             _ => (),
         }
     }
@@ -221,17 +736,38 @@ fn run() -> Result<()> {
     // SimpleLogger will send errors to stderr and anything less to stdout.
     SimpleLogger::init(LevelFilter::Info, LogConfig::default()).context(error::LoggerSnafu)?;
 
-    let mut modes = run_gpu_query()?;
+    let mut modes = run_gpu_query(&args)?;
     let (is_mig_capable, overall_mig_mode) = analyze_mig_status(&modes)?;
 
     if is_mig_capable {
-        if overall_mig_mode == MigState::Disabled {
-            let _ = set_mig_mode()?;
-            modes = run_gpu_query()?;
-            let _ = analyze_mig_status(&modes)?;
-            let _ = command("apiclient", ["reboot"])?;
-        } else if overall_mig_mode == MigState::Enabled {
-            let _ = set_mig_profile()?;
+        let capable = mig_capable_devices(&modes);
+
+        match reconcile_reboot_guard(&overall_mig_mode)? {
+            MigAction::RequestReboot => {
+                let _ = set_mig_mode(&args, &capable)?;
+                modes = run_gpu_query(&args)?;
+                let _ = analyze_mig_status(&modes)?;
+
+                // Only mark the reboot as requested once we're actually about to
+                // issue it — if `set_mig_mode` or the re-query above failed, we'd
+                // have already returned via `?` with no marker written, so the
+                // next run retries cleanly instead of waiting on a reboot that
+                // never happened.
+                RebootGuardState {
+                    target_mode: MigState::Enabled,
+                    reboot_requested: true,
+                    attempts: 0,
+                }
+                .save()?;
+
+                let _ = command("apiclient", ["reboot"])?;
+            }
+            MigAction::SetProfile => {
+                let config = load_mig_config(&args.config_path)?;
+                config.validate(&modes)?;
+                let _ = set_mig_profile(&config, &capable)?;
+            }
+            MigAction::WaitForReboot | MigAction::None => (),
         }
     }
 
@@ -251,6 +787,7 @@ fn main() {
 
 mod error {
     use snafu::Snafu;
+    use std::path::PathBuf;
     use std::process::{Command, Output};
 
     #[derive(Debug, Snafu)]
@@ -269,6 +806,54 @@ mod error {
         #[snafu(display("Logger setup error: {}", source))]
         Logger { source: log::SetLoggerError },
 
+        #[snafu(display("NVML error: {}", source))]
+        Nvml { source: nvml_wrapper::error::NvmlError },
+
+        #[snafu(display("Failed to read MIG config '{}': {}", path.display(), source))]
+        ConfigRead {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to parse MIG config '{}': {}", path.display(), source))]
+        ConfigParse {
+            path: PathBuf,
+            source: toml::de::Error,
+        },
+
+        #[snafu(display("Invalid MIG config: {}", message))]
+        InvalidMigConfig { message: String },
+
+        #[snafu(display("Invalid GPU index '{}' in 'gpu_profiles' key: {}", index, source))]
+        ConfigGpuIndex {
+            index: String,
+            source: std::num::ParseIntError,
+        },
+
+        #[snafu(display("Profile '{}' is not supported on this GPU", name))]
+        UnsupportedProfile { name: String },
+
+        #[snafu(display("Failed to read reboot-guard state '{}': {}", path.display(), source))]
+        StateRead {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Failed to write reboot-guard state '{}': {}", path.display(), source))]
+        StateWrite {
+            path: PathBuf,
+            source: std::io::Error,
+        },
+
+        #[snafu(display("Reboot-guard state '{}' is malformed", path.display()))]
+        InvalidState { path: PathBuf },
+
+        #[snafu(display("Invalid GPU index '{}' in nvidia-smi output: {}", index, source))]
+        GpuIndex {
+            index: String,
+            source: std::num::ParseIntError,
+        },
+
         #[snafu(display("Invalid log level '{}'", log_level))]
         LogLevel {
             log_level: String,
@@ -282,6 +867,196 @@ mod error {
 
 type Result<T> = std::result::Result<T, error::Error>;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(index: u32, uuid: &str) -> GpuMigInfo {
+        GpuMigInfo {
+            uuid: uuid.to_string(),
+            name: "NVIDIA A100-SXM4-40GB".to_string(),
+            index,
+            current: MigState::Disabled,
+            pending: MigState::Disabled,
+        }
+    }
+
+    fn single_strategy_config(profiles: Vec<&str>) -> MigConfig {
+        MigConfig {
+            strategy: MigStrategy::Single,
+            profiles: profiles.into_iter().map(String::from).collect(),
+            gpu_profiles: BTreeMap::new(),
+            gpu_profiles_by_uuid: BTreeMap::new(),
+        }
+    }
+
+    fn mixed_strategy_config() -> MigConfig {
+        MigConfig {
+            strategy: MigStrategy::Mixed,
+            profiles: Vec::new(),
+            gpu_profiles: BTreeMap::from([("0".to_string(), vec!["3g.20gb".to_string()])]),
+            gpu_profiles_by_uuid: BTreeMap::from([("GPU-uuid-1".to_string(), vec!["1g.5gb".to_string()])]),
+        }
+    }
+
+    #[test]
+    fn single_strategy_shares_one_profile_list_across_every_device() {
+        let config = single_strategy_config(vec!["3g.20gb"]);
+        let devices = [device(0, "GPU-uuid-0"), device(1, "GPU-uuid-1")];
+
+        for d in &devices {
+            assert_eq!(config.profiles_for(d), Some(&vec!["3g.20gb".to_string()]));
+        }
+    }
+
+    #[test]
+    fn single_strategy_without_a_profiles_list_fails_validation() {
+        let config = single_strategy_config(vec![]);
+        let devices = [device(0, "GPU-uuid-0")];
+        assert!(config.validate(&devices).is_err());
+    }
+
+    #[test]
+    fn mixed_strategy_prefers_uuid_over_index() {
+        let config = mixed_strategy_config();
+        // Index 0 would match `gpu_profiles`, but this device's UUID also
+        // has its own entry, which should win.
+        let device = device(0, "GPU-uuid-1");
+        assert_eq!(config.profiles_for(&device), Some(&vec!["1g.5gb".to_string()]));
+    }
+
+    #[test]
+    fn mixed_strategy_falls_back_to_index_when_uuid_has_no_entry() {
+        let config = mixed_strategy_config();
+        let device = device(0, "GPU-uuid-0");
+        assert_eq!(config.profiles_for(&device), Some(&vec!["3g.20gb".to_string()]));
+    }
+
+    #[test]
+    fn mixed_strategy_with_no_entry_for_a_device_configures_nothing() {
+        let config = mixed_strategy_config();
+        let device = device(5, "GPU-uuid-5");
+        assert_eq!(config.profiles_for(&device), None);
+    }
+
+    #[test]
+    fn mixed_strategy_gpu_profiles_key_survives_a_toml_round_trip() {
+        // This is the bug the test guards against: TOML table keys are
+        // always strings, so a `BTreeMap<usize, _>` field fails to
+        // deserialize at all.
+        let toml_str = "strategy = \"mixed\"\n[gpu_profiles]\n0 = [\"3g.20gb\"]\n";
+        let config: MigConfig = toml::from_str(toml_str).unwrap();
+        assert_eq!(
+            config.profiles_for(&device(0, "GPU-uuid-0")),
+            Some(&vec!["3g.20gb".to_string()])
+        );
+    }
+
+    #[test]
+    fn mixed_strategy_rejects_an_out_of_range_index() {
+        let config = MigConfig {
+            strategy: MigStrategy::Mixed,
+            profiles: Vec::new(),
+            gpu_profiles: BTreeMap::from([("1".to_string(), vec!["3g.20gb".to_string()])]),
+            gpu_profiles_by_uuid: BTreeMap::new(),
+        };
+        let devices = [device(0, "GPU-uuid-0")];
+        assert!(config.validate(&devices).is_err());
+    }
+
+    #[test]
+    fn mixed_strategy_rejects_an_unknown_uuid() {
+        let config = MigConfig {
+            strategy: MigStrategy::Mixed,
+            profiles: Vec::new(),
+            gpu_profiles: BTreeMap::new(),
+            gpu_profiles_by_uuid: BTreeMap::from([("GPU-uuid-missing".to_string(), vec!["1g.5gb".to_string()])]),
+        };
+        let devices = [device(0, "GPU-uuid-0")];
+        assert!(config.validate(&devices).is_err());
+    }
+
+    fn requested(target_mode: MigState, attempts: u32) -> RebootGuardState {
+        RebootGuardState {
+            target_mode,
+            reboot_requested: true,
+            attempts,
+        }
+    }
+
+    #[test]
+    fn reboot_guard_state_round_trips_through_its_text_format() {
+        let state = requested(MigState::Enabled, 2);
+        let parsed = RebootGuardState::parse(&state.serialize()).unwrap();
+        assert_eq!(state, parsed);
+    }
+
+    #[test]
+    fn reboot_guard_state_parse_rejects_missing_target_mode() {
+        assert_eq!(RebootGuardState::parse("reboot_requested=true\nattempts=1\n"), None);
+    }
+
+    #[test]
+    fn enabled_clears_the_guard_and_moves_on_to_setting_profiles() {
+        let (action, update) = decide_reboot_guard(&MigState::Enabled, Some(&requested(MigState::Enabled, 1)));
+        assert_eq!(action, MigAction::SetProfile);
+        assert_eq!(update, GuardUpdate::Clear);
+    }
+
+    #[test]
+    fn disabled_with_no_marker_requests_a_fresh_reboot() {
+        let (action, update) = decide_reboot_guard(&MigState::Disabled, None);
+        assert_eq!(action, MigAction::RequestReboot);
+        assert_eq!(update, GuardUpdate::None);
+    }
+
+    #[test]
+    fn disabled_with_a_pending_reboot_backs_off_until_the_attempt_limit() {
+        let (action, update) =
+            decide_reboot_guard(&MigState::Disabled, Some(&requested(MigState::Enabled, 0)));
+        assert_eq!(action, MigAction::WaitForReboot);
+        assert_eq!(update, GuardUpdate::Save(requested(MigState::Enabled, 1)));
+    }
+
+    #[test]
+    fn disabled_past_the_attempt_limit_clears_the_marker_and_retries() {
+        let (action, update) = decide_reboot_guard(
+            &MigState::Disabled,
+            Some(&requested(MigState::Enabled, MAX_REBOOT_ATTEMPTS)),
+        );
+        assert_eq!(action, MigAction::RequestReboot);
+        assert_eq!(update, GuardUpdate::Clear);
+    }
+
+    // Regression test: a flaky `apiclient reboot` call (or a reboot that
+    // never brings MIG up) leaves the device in `Transition`, not back in
+    // `Disabled` — this arm used to wait forever instead of sharing the
+    // same attempt bound as the `Disabled` arm above.
+    #[test]
+    fn transition_with_a_pending_reboot_is_bounded_by_the_same_attempt_limit() {
+        let (action, update) = decide_reboot_guard(
+            &MigState::Transition,
+            Some(&requested(MigState::Enabled, MAX_REBOOT_ATTEMPTS)),
+        );
+        assert_eq!(action, MigAction::RequestReboot);
+        assert_eq!(update, GuardUpdate::Clear);
+    }
+
+    #[test]
+    fn transition_with_no_marker_just_waits() {
+        let (action, update) = decide_reboot_guard(&MigState::Transition, None);
+        assert_eq!(action, MigAction::WaitForReboot);
+        assert_eq!(update, GuardUpdate::None);
+    }
+
+    #[test]
+    fn unknown_mode_takes_no_action() {
+        let (action, update) = decide_reboot_guard(&MigState::Unknown, None);
+        assert_eq!(action, MigAction::None);
+        assert_eq!(update, GuardUpdate::None);
+    }
+}
+
 /*
 Oct 18 21:18:27 ip-192-168-113-68.us-west-2.compute.internal systemd[1]: Starting NVIDIA MIG manager service...
 Oct 18 21:18:27 ip-192-168-113-68.us-west-2.compute.internal nvidia-migmanager[15644]: entered main function here0